@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use syn::{Ident, Path};
+use syn::{Ident, Path, Ty};
 use syntax::check::{self, Idle, Init};
 use syntax::{self, Resources, Statics};
 
@@ -11,6 +11,8 @@ pub struct App {
     pub idle: Idle,
     pub init: Init,
     pub resources: Statics,
+    pub resource_ceilings: HashMap<Ident, u8>,
+    pub dispatch_groups: DispatchGroups,
     pub tasks: Tasks,
 }
 
@@ -18,16 +20,28 @@ pub type Tasks = HashMap<Ident, Task>;
 
 #[allow(non_camel_case_types)]
 pub enum Exception {
-    PENDSV,
+    NMI,
+    HARD_FAULT,
+    MEM_MANAGE,
+    BUS_FAULT,
+    USAGE_FAULT,
     SVCALL,
+    DEBUG_MONITOR,
+    PENDSV,
     SYS_TICK,
 }
 
 impl Exception {
     pub fn from(s: &str) -> Option<Self> {
         Some(match s {
-            "PENDSV" => Exception::PENDSV,
+            "NMI" => Exception::NMI,
+            "HARD_FAULT" => Exception::HARD_FAULT,
+            "MEM_MANAGE" => Exception::MEM_MANAGE,
+            "BUS_FAULT" => Exception::BUS_FAULT,
+            "USAGE_FAULT" => Exception::USAGE_FAULT,
             "SVCALL" => Exception::SVCALL,
+            "DEBUG_MONITOR" => Exception::DEBUG_MONITOR,
+            "PENDSV" => Exception::PENDSV,
             "SYS_TICK" => Exception::SYS_TICK,
             _ => return None,
         })
@@ -35,41 +49,99 @@ impl Exception {
 
     pub fn nr(&self) -> usize {
         match *self {
-            Exception::PENDSV => 14,
+            Exception::NMI => 2,
+            Exception::HARD_FAULT => 3,
+            Exception::MEM_MANAGE => 4,
+            Exception::BUS_FAULT => 5,
+            Exception::USAGE_FAULT => 6,
             Exception::SVCALL => 11,
+            Exception::DEBUG_MONITOR => 12,
+            Exception::PENDSV => 14,
             Exception::SYS_TICK => 15,
         }
     }
+
+    /// NMI and HardFault run above any BASEPRI level -- they can't be
+    /// masked -- so they can never safely be the "masked out" side of a
+    /// critical section. The other faults (MemManage, BusFault, UsageFault,
+    /// DebugMonitor) are configurable priority like any other exception and
+    /// may take part in the ceiling protocol normally.
+    pub fn is_maskable(&self) -> bool {
+        match *self {
+            Exception::NMI | Exception::HARD_FAULT => false,
+            _ => true,
+        }
+    }
 }
 
 pub enum Kind {
     Exception(Exception),
-    Interrupt { enabled: bool },
+    Interrupt {
+        enabled: bool,
+    },
+    /// A software task: not bound to a hardware vector, instead `spawn`ed
+    /// from other tasks and run by the dispatcher interrupt its priority
+    /// group is assigned to.
+    Software {
+        input: Ty,
+    },
 }
 
 pub struct Task {
+    /// The task's own name, carried on the struct (rather than only
+    /// available as a `Tasks` key) so diagnostics can label *this* task's
+    /// site without the caller having to thread it through separately.
+    pub name: Ident,
     pub kind: Kind,
     pub path: Path,
     pub priority: u8,
     pub resources: Resources,
+    /// Software tasks this task may `spawn`, paired with the message type
+    /// asserted for that edge at the `spawn` call site.
+    pub spawns: Vec<(Ident, Ty)>,
 }
 
+/// A priority level's worth of software tasks, all dispatched by a single
+/// shared interrupt pended whenever one of them is spawned.
+pub struct DispatchGroup {
+    pub tasks: Vec<Ident>,
+    /// The interrupt code generation binds this group's dispatcher to. See
+    /// `dispatch_groups` for how it's picked and what's and isn't checked.
+    pub interrupt: Ident,
+}
+
+/// Software tasks grouped by priority.
+pub type DispatchGroups = HashMap<u8, DispatchGroup>;
+
 pub fn app(app: check::App) -> Result<App> {
     println!("-- checking tasks --");
+    let device = app.device;
+    let idle = app.idle;
+    let init = app.init;
+    let resources = app.resources;
+    let tasks: Tasks = app
+        .tasks
+        .into_iter()
+        .map(|(k, v)| {
+            let v = ::check::task(k.clone(), v).chain_err(|| format!("checking task `{}`", k))?;
+
+            Ok((k, v))
+        })
+        .collect::<Result<_>>()?;
+
+    let resource_ceilings = ceilings(&resources, &idle, &tasks);
+
+    println!("-- checking dispatch --");
+    let dispatch_groups = dispatch_groups(&tasks).chain_err(|| "checking software tasks")?;
+
     let app = App {
-        device: app.device,
-        idle: app.idle,
-        init: app.init,
-        resources: app.resources,
-        tasks: app.tasks
-            .into_iter()
-            .map(|(k, v)| {
-                let v =
-                    ::check::task(k.as_ref(), v).chain_err(|| format!("checking task `{}`", k))?;
-
-                Ok((k, v))
-            })
-            .collect::<Result<_>>()?,
+        device,
+        idle,
+        init,
+        resources,
+        resource_ceilings,
+        dispatch_groups,
+        tasks,
     };
 
     println!("-- checking resources --");
@@ -78,32 +150,224 @@ pub fn app(app: check::App) -> Result<App> {
     Ok(app)
 }
 
+/// Checks every `spawn` edge declared on a task: that the target exists,
+/// that it's a software task (only those are dispatcher-run and thus
+/// `spawn`able -- a hardware task already runs on its own vector), that a
+/// task doesn't spawn itself, and that the asserted message type at the
+/// call site matches the target's declared `input` type. The target's own
+/// resources already participate in ceiling derivation like any other
+/// task's (see `ceilings`), so no extra step is needed for that part.
+fn spawns(name: &Ident, task: &Task, tasks: &Tasks) -> Result<()> {
+    for &(ref target, ref msg_ty) in &task.spawns {
+        ensure!(
+            target != name,
+            "task `{}` spawns itself, but a task can't `spawn` its own dispatch",
+            name
+        );
+
+        let spawned = tasks.get(target).ok_or_else(|| {
+            format!(
+                "task `{}` spawns `{}`, but no task with that name is declared",
+                name, target
+            )
+        })?;
+
+        match spawned.kind {
+            Kind::Software { ref input } => ensure!(
+                input == msg_ty,
+                "task `{}` spawns `{}` with message type `{:?}`, but `{}`'s declared `input` \
+                 type is `{:?}` -- the two must match",
+                name,
+                target,
+                msg_ty,
+                target,
+                input
+            ),
+            _ => bail!(
+                "task `{}` spawns `{}`, but `{}` isn't a software task -- it's bound to its \
+                 own hardware vector and can't be dispatched by `spawn`",
+                name,
+                target,
+                target
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Groups software tasks by priority and assigns each group a dispatcher
+/// interrupt, checking along the way that every `spawn` edge targets a
+/// declared software task with a matching message type.
+///
+/// Capacity: this checker only sees the tasks declared in this `tasks!`
+/// invocation, not the device crate's interrupt table (`App::device` is
+/// just the path to it), so it has no way to know how many vectors the
+/// target actually has left over. What it *can* check from purely local
+/// information is that the name it's about to reserve for a dispatcher
+/// doesn't collide with an interrupt/exception a hardware task already
+/// binds to. Validating a dispatcher against the real, complete vector
+/// table -- and picking a genuinely unused one rather than this fixed
+/// `__rtfm_dispatch_<priority>` naming scheme -- needs device crate
+/// introspection this checker doesn't do; that's left to code generation.
+fn dispatch_groups(tasks: &Tasks) -> Result<DispatchGroups> {
+    let mut by_priority: HashMap<u8, Vec<Ident>> = HashMap::new();
+
+    for (name, task) in tasks {
+        spawns(name, task, tasks).chain_err(|| format!("checking task `{}`'s `spawn`s", name))?;
+
+        if let Kind::Software { .. } = task.kind {
+            ensure!(
+                task.priority > 0,
+                "software task `{}` must run at a priority greater than 0 (priority 0 is \
+                 reserved for `idle`)",
+                name
+            );
+
+            by_priority
+                .entry(task.priority)
+                .or_insert_with(Vec::new)
+                .push(name.clone());
+        }
+    }
+
+    let mut groups = DispatchGroups::new();
+
+    for (priority, mut names) in by_priority {
+        names.sort();
+
+        let interrupt = Ident::new(format!("__rtfm_dispatch_{}", priority));
+
+        let collision = tasks.iter().find(|&(hw_name, hw_task)| {
+            let is_hardware = match hw_task.kind {
+                Kind::Exception(_) | Kind::Interrupt { .. } => true,
+                Kind::Software { .. } => false,
+            };
+
+            // A hardware task is bound to the vector its name spells (see `task`).
+            is_hardware && *hw_name == interrupt
+        });
+
+        if let Some((hw_name, _)) = collision {
+            bail!(
+                "the dispatcher this checker would reserve for priority {} (`{}`) is already \
+                 bound to hardware task `{}` -- rename that task's interrupt/exception, or pick \
+                 a different priority for its software tasks",
+                priority,
+                interrupt,
+                hw_name
+            );
+        }
+
+        groups.insert(
+            priority,
+            DispatchGroup {
+                tasks: names,
+                interrupt,
+            },
+        );
+    }
+
+    Ok(groups)
+}
+
+/// Computes the Immediate Ceiling Priority Protocol (ICPP) ceiling of every
+/// shared resource: the highest priority among the tasks (and `idle`, which
+/// always runs at priority 0) that access it. `init` is excluded because it
+/// runs before the scheduler is started and can't be preempted.
+///
+/// This is what code generation raises BASEPRI to inside a resource's
+/// critical section, instead of trusting a hand-written `C<n>` token.
+fn ceilings(resources: &Statics, idle: &Idle, tasks: &Tasks) -> HashMap<Ident, u8> {
+    resources
+        .keys()
+        .map(|resource| {
+            let ceiling = tasks
+                .values()
+                .filter(|task| task.resources.contains(resource))
+                .map(|task| task.priority)
+                .chain(if idle.resources.contains(resource) {
+                    Some(0)
+                } else {
+                    None
+                })
+                .max()
+                .unwrap_or(0);
+
+            (resource.clone(), ceiling)
+        })
+        .collect()
+}
+
+/// Joins the sites that contributed to a conflict with the detail each one
+/// adds, then a closing sentence on why they disagree -- "`x`: claims `y`.
+/// `z`: also claims `y`. <reason>" rather than one bare name.
+///
+/// This is labeled prose, not a source diagnostic: `App`/`Task`/`Statics`
+/// carry no source positions to point a snippet at, so this makes no
+/// attempt to mimic rustc's `-->`/`file:line:col` output the way an actual
+/// span-backed diagnostic would.
+fn labeled(sites: &[(String, String)], reason: &str) -> String {
+    let mut msg = String::new();
+
+    for site in sites {
+        msg.push_str(&format!("{}: {}. ", site.0, site.1));
+    }
+
+    msg.push_str(reason);
+    msg
+}
+
 fn resources(app: &App) -> Result<()> {
     for name in &app.init.resources {
         if let Some(resource) = app.resources.get(name) {
             ensure!(
                 resource.expr.is_some(),
-                "resource `{}`, allocated to `init`, must have an initial value",
+                "resource `{}` is claimed by `init`, but has no initial value -- `init` is the \
+                 only place that value could come from, so one must be given in `resources`",
                 name
             );
         } else {
             bail!(
-                "resource `{}`, allocated to `init`, must be a data resource",
+                "`init` claims `{}`, but no resource with that name is declared in `resources`",
                 name
             );
         }
 
         ensure!(
             !app.idle.resources.contains(name),
-            "resources assigned to `init` can't be shared with `idle`"
+            "resource `{}` is claimed by both `init` and `idle`, but `init` runs to completion \
+             before `idle` (or any task) starts, so the two can never actually share it",
+            name
         );
 
-        ensure!(
-            app.tasks
-                .iter()
-                .all(|(_, task)| !task.resources.contains(name)),
-            "resources assigned to `init` can't be shared with tasks"
-        )
+        if let Some((task_name, task)) = app
+            .tasks
+            .iter()
+            .find(|&(_, task)| task.resources.contains(name))
+        {
+            bail!(
+                "{}",
+                labeled(
+                    &[
+                        (
+                            "`init`'s `resources` list".to_owned(),
+                            format!("claims `{}`", name),
+                        ),
+                        (
+                            format!("task `{}`'s `priority` field", task_name),
+                            format!(
+                                "`{}` also claims `{}`, and runs at priority {}",
+                                task_name, name, task.priority
+                            ),
+                        ),
+                    ],
+                    "`init` runs before the scheduler starts, so no task can ever observe its \
+                     post-`init` state through a critical section -- move the resource's \
+                     initial value out of `init`, or stop sharing it with this task"
+                )
+            );
+        }
     }
 
     for resource in app.resources.keys() {
@@ -115,58 +379,200 @@ fn resources(app: &App) -> Result<()> {
             continue;
         }
 
-        if app.tasks
+        if app
+            .tasks
             .values()
             .any(|task| task.resources.contains(resource))
         {
             continue;
         }
 
-        bail!("resource `{}` is unused", resource);
+        bail!(
+            "{}",
+            labeled(
+                &[(
+                    format!("resource `{}`'s declaration", resource),
+                    "declared here, but claimed by nothing".to_owned(),
+                )],
+                "`init`, `idle` and every task's `resources` list were checked and none of them \
+                 claim it -- remove it, or add it to whatever should own it"
+            )
+        );
     }
 
     for (name, task) in &app.tasks {
         for resource in &task.resources {
-            ensure!(
-                app.resources.contains_key(&resource),
-                "task {} contains an undeclared resource with name {}",
-                name,
-                resource
-            );
+            if !app.resources.contains_key(&resource) {
+                bail!(
+                    "{}",
+                    labeled(
+                        &[(
+                            format!("task `{}`'s `resources` list", name),
+                            format!(
+                                "claims `{}` (task `{}` runs at priority {})",
+                                resource, name, task.priority
+                            ),
+                        )],
+                        &format!(
+                            "no resource named `{}` is declared in `resources` -- fix the typo, \
+                             or add the missing `resources!` entry",
+                            resource
+                        )
+                    )
+                );
+            }
+        }
+    }
+
+    // Backwards compatibility: a resource may still carry an explicit `C<n>`
+    // ceiling token (parsed by the `resources!` macro into `Static.ceiling`)
+    // instead of relying on automatic derivation. Where one is present it
+    // must agree with the ceiling we just computed -- we no longer trust it
+    // blindly.
+    for (name, resource) in &app.resources {
+        if let Some(token) = resource.ceiling {
+            let computed = app.resource_ceilings[name];
+
+            if token != computed {
+                let sharers = app
+                    .tasks
+                    .iter()
+                    .filter(|&(_, task)| task.resources.contains(name))
+                    .map(|(task_name, task)| {
+                        format!("task `{}` runs at priority {}", task_name, task.priority)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let sharers = if sharers.is_empty() {
+                    "nothing shares it".to_owned()
+                } else {
+                    sharers
+                };
+
+                bail!(
+                    "{}",
+                    labeled(
+                        &[
+                            (
+                                format!("resource `{}`'s declaration", name),
+                                format!(
+                                    "ceiling is computed as `C{}` (from: {})",
+                                    computed, sharers
+                                ),
+                            ),
+                            (
+                                format!("resource `{}`'s ceiling token", name),
+                                format!("requests `C{}` here", token),
+                            ),
+                        ],
+                        &format!(
+                            "the explicit ceiling token on `{}` must match the computed \
+                             ceiling; update it to `C{}`, or remove it and let it be derived \
+                             automatically",
+                            name, computed
+                        )
+                    )
+                );
+            }
+        }
+    }
+
+    // NMI and HardFault run above any BASEPRI level, so raising it can't
+    // mask them out: they can never be the "other side" of a critical
+    // section. That's only a problem for resources they actually *share*
+    // with something else -- a resource only this handler ever touches
+    // needs no critical section, so it's fine even here.
+    for (name, task) in &app.tasks {
+        let e = match task.kind {
+            Kind::Exception(ref e) => e,
+            _ => continue,
+        };
+
+        if e.is_maskable() {
+            continue;
+        }
+
+        for resource in &task.resources {
+            let shared_elsewhere = app.idle.resources.contains(resource)
+                || app.tasks.iter().any(|(other_name, other)| {
+                    other_name != name && other.resources.contains(resource)
+                });
+
+            if shared_elsewhere {
+                bail!(
+                    "{}",
+                    labeled(
+                        &[
+                            (
+                                format!("task `{}`", name),
+                                "can't be masked by raising BASEPRI".to_owned(),
+                            ),
+                            (
+                                format!("resource `{}`'s declaration", resource),
+                                format!(
+                                    "ceiling is `C{}`, meaning something else also claims it",
+                                    app.resource_ceilings[resource]
+                                ),
+                            ),
+                        ],
+                        &format!(
+                            "`{}` can never safely enter the critical section needed to share \
+                             `{}` with anything else -- stop sharing it, or move `{}` off this \
+                             fault handler",
+                            name, resource, resource
+                        )
+                    )
+                );
+            }
         }
     }
 
     Ok(())
 }
 
-fn task(name: &str, task: syntax::check::Task) -> Result<Task> {
-    let kind = match Exception::from(name) {
+fn task(name: Ident, task: syntax::check::Task) -> Result<Task> {
+    let kind = match Exception::from(name.as_ref()) {
         Some(e) => {
             ensure!(
                 task.enabled.is_none(),
                 "`enabled` field is not valid for exceptions"
             );
 
+            // Whether any of `task.resources` is actually *shared* -- and so
+            // needs a critical section this exception can't safely enter --
+            // can only be known once every task's claims are in, so that
+            // gate lives in `resources()` instead of here.
             Kind::Exception(e)
         }
         None => {
-            if task.enabled == Some(true) {
-                bail!(
-                    "`enabled: true` is the default value; this line can be \
-                     omitted"
+            if let Some(input) = task.input {
+                ensure!(
+                    task.enabled.is_none(),
+                    "`enabled` field is not valid for software tasks"
                 );
-            }
 
-            Kind::Interrupt {
-                enabled: task.enabled.unwrap_or(true),
+                Kind::Software { input }
+            } else {
+                if task.enabled == Some(true) {
+                    bail!(
+                        "`enabled: true` is the default value; this line can be \
+                     omitted"
+                    );
+                }
+
+                Kind::Interrupt {
+                    enabled: task.enabled.unwrap_or(true),
+                }
             }
         }
     };
 
     Ok(Task {
+        name,
         kind,
         path: task.path.ok_or("`path` field is missing")?,
         priority: task.priority.unwrap_or(1),
         resources: task.resources,
+        spawns: task.spawns,
     })
 }
@@ -0,0 +1,40 @@
+//! The raw AST `tasks!`/`resources!` parse into, before `macros::check`
+//! validates it into its own, checked `App`.
+//!
+//! These types only hold data; nothing here is itself validated -- e.g.
+//! `Task::priority` being `None` just means the user didn't write a
+//! `priority` field (`macros::check::task` then defaults it to 1).
+
+use syn::{Ident, Path, Ty};
+
+use Resources;
+
+pub struct App {
+    pub device: Path,
+    pub idle: Idle,
+    pub init: Init,
+    pub resources: ::Statics,
+    pub tasks: ::std::collections::HashMap<Ident, Task>,
+}
+
+pub struct Idle {
+    pub resources: Resources,
+}
+
+pub struct Init {
+    pub resources: Resources,
+}
+
+pub struct Task {
+    pub enabled: Option<bool>,
+    pub path: Option<Path>,
+    pub priority: Option<u8>,
+    pub resources: Resources,
+    /// The message type a software task's `spawn`ers must supply, written
+    /// as `input: SomeType` in the task's `tasks!` entry. `None` for
+    /// hardware tasks (exceptions and interrupts), which aren't `spawn`able.
+    pub input: Option<Ty>,
+    /// This task's outgoing `spawn` edges: the software task it targets,
+    /// paired with the message type asserted at the `spawn` call site.
+    pub spawns: Vec<(Ident, Ty)>,
+}
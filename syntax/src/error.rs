@@ -0,0 +1,5 @@
+//! The `Result`/`bail!`/`ensure!` vocabulary `macros::check` builds its
+//! diagnostics on, re-exported from a single `error_chain!` so every caller
+//! gets the same `Error`/`ErrorKind` types.
+
+error_chain! {}
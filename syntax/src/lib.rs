@@ -0,0 +1,39 @@
+//! The AST `tasks!`/`resources!` expand into and the types `macros::check`
+//! validates.
+//!
+//! This crate holds data definitions only: the `tasks!`/`resources!` token
+//! parsers that actually build these values from a user's invocation aren't
+//! part of this checkout, so nothing here claims to parse anything -- it's
+//! the shape `macros::check` expects to receive, documented at the fields
+//! it reads (`Task::input`, `Task::spawns`, `Static::ceiling`).
+
+#[macro_use]
+extern crate error_chain;
+extern crate syn;
+
+pub mod check;
+pub mod error;
+
+use std::collections::{HashMap, HashSet};
+
+use syn::{Expr, Ident, Ty};
+
+/// The set of resources a task, `idle`, or `init` claims in its `resources`
+/// list.
+pub type Resources = HashSet<Ident>;
+
+/// A single entry in `resources! { ... }`, keyed by name in `Statics`.
+pub struct Static {
+    pub ty: Ty,
+    /// The value given in `resources!`, if any. Required for any resource
+    /// `init` claims (see `macros::check::resources`).
+    pub expr: Option<Expr>,
+    /// The resource's ceiling, if written explicitly as a `C<n>` token.
+    /// Kept only for backwards compatibility: `macros::check::ceilings`
+    /// derives the real ceiling automatically, and `macros::check::resources`
+    /// rejects an explicit token that disagrees with it.
+    pub ceiling: Option<u8>,
+}
+
+/// All resources declared in `resources! { ... }`, by name.
+pub type Statics = HashMap<Ident, Static>;
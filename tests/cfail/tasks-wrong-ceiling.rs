@@ -1,4 +1,14 @@
 // error-pattern: mismatched types
+//
+// `j1` declares no `resources`, so `App::resource_ceilings` has nothing to
+// say about it -- this fixture is a plain type-level proof-token mismatch
+// between `_prio: P1` and `_ceil: C2` on `j1`'s own definition, unrelated
+// to ceiling derivation. It's unaffected by automatic ceiling derivation
+// and still fails exactly as before with `mismatched types`. A mismatched
+// explicit `C<n>` token on a *shared* resource declared in `resources!` is
+// a different path, covered by `check::resources`'s ceiling-token
+// validation (see `check.rs`), and surfaces as a macro-expansion error
+// instead.
 
 #![feature(used)]
 